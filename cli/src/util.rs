@@ -1,29 +1,61 @@
+#[cfg(feature = "qrcode")]
+extern crate atty;
 #[cfg(feature = "clipboard")]
 extern crate clipboard;
 extern crate colored;
+// NOTE: the crate's `bracketed-paste` Cargo feature must be enabled
+// alongside our own `rawmode` feature, or `Event::Paste` is never
+// emitted and pasted input falls back to being parsed key-by-key.
+#[cfg(feature = "rawmode")]
+extern crate crossterm;
+#[cfg(feature = "keyring")]
+extern crate keyring;
 extern crate open;
+#[cfg(feature = "qrcode")]
+extern crate qrcode;
+extern crate rand;
+#[cfg(feature = "qrcode")]
+extern crate term_size;
 
 use std::env::current_exe;
 #[cfg(feature = "clipboard")]
 use std::error::Error as StdError;
 use std::fmt::{Debug, Display};
 use std::io::{
+    BufRead,
+    BufReader,
     Error as IoError,
     stdin,
     stderr,
     Write,
 };
-use std::process::{exit, ExitStatus};
+use std::process::{exit, Command, ExitStatus, Stdio};
 
 #[cfg(feature = "clipboard")]
 use self::clipboard::{ClipboardContext, ClipboardProvider};
 use self::colored::*;
+#[cfg(feature = "rawmode")]
+use self::crossterm::event::{read, Event, KeyCode, KeyModifiers};
+#[cfg(feature = "rawmode")]
+use self::crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+#[cfg(feature = "keyring")]
+use self::keyring::Entry;
+#[cfg(feature = "qrcode")]
+use self::qrcode::QrCode;
+#[cfg(feature = "qrcode")]
+use self::qrcode::render::unicode;
+use self::rand::Rng;
+use self::rand::rngs::OsRng;
 use failure::{err_msg, Fail};
 use ffsend_api::url::Url;
 use rpassword::prompt_password_stderr;
 
 use cmd::matcher::MainMatcher;
 
+/// The keyring service name secrets are namespaced under.
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "ffsend";
+
 /// Print a success message.
 pub fn print_success(msg: &str) {
     println!("{}", msg.green());
@@ -183,6 +215,45 @@ pub fn open_path(path: &str) -> Result<ExitStatus, IoError> {
     open::that(path)
 }
 
+/// Render the given `url` as a QR code to the terminal, using half-block
+/// unicode characters so two QR rows fit on a single text line.
+///
+/// Falls back to printing the plain URL if stdout isn't a TTY, or if the
+/// terminal isn't wide enough to fit the symbol.
+#[cfg(feature = "qrcode")]
+pub fn print_qr(url: &Url) {
+    // Don't render to a non-TTY, such as a pipe or file redirect
+    if !atty::is(atty::Stream::Stdout) {
+        println!("{}", url);
+        return;
+    }
+
+    let code = match QrCode::new(url.as_str()) {
+        Ok(code) => code,
+        Err(_) => {
+            println!("{}", url);
+            return;
+        },
+    };
+
+    // Include the library's own quiet zone, and measure the line it
+    // actually renders, rather than estimating from the raw module count
+    let image = code.render::<unicode::Dense1x2>()
+        .quiet_zone(true)
+        .build();
+    let width = image.lines().next().map_or(0, |line| line.chars().count());
+
+    // Fall back if the terminal is too narrow to fit the rendered symbol
+    if let Some((cols, _)) = term_size::dimensions() {
+        if cols < width {
+            println!("{}", url);
+            return;
+        }
+    }
+
+    println!("{}", image);
+}
+
 /// Set the clipboard of the user to the given `content` string.
 #[cfg(feature = "clipboard")]
 pub fn set_clipboard(content: String) -> Result<(), Box<StdError>> {
@@ -190,6 +261,77 @@ pub fn set_clipboard(content: String) -> Result<(), Box<StdError>> {
     context.set_contents(content)
 }
 
+/// A wrapper around the OS secure credential store (Secret Service /
+/// macOS Keychain / Windows Credential Manager), used to persist
+/// per-file owner tokens and passwords keyed by the share URL.
+///
+/// Backend errors (such as no secret service being available on a
+/// headless machine) are swallowed, so callers can always fall back to
+/// prompting the user.
+#[cfg(feature = "keyring")]
+pub struct Secrets;
+
+#[cfg(feature = "keyring")]
+impl Secrets {
+    /// Get the stored owner token for the given `url`, if any.
+    pub fn get_owner_token(url: &Url) -> Option<String> {
+        Self::get(&Self::owner_token_key(url))
+    }
+
+    /// Store the given owner `token` for the given `url`.
+    pub fn set_owner_token(url: &Url, token: &str) {
+        Self::set(&Self::owner_token_key(url), token);
+    }
+
+    /// Get the stored password for the given `url`, if any.
+    pub fn get_password(url: &Url) -> Option<String> {
+        Self::get(&Self::password_key(url))
+    }
+
+    /// Store the given `password` for the given `url`.
+    pub fn set_password(url: &Url, password: &str) {
+        Self::set(&Self::password_key(url), password);
+    }
+
+    /// Wipe all secrets that were stored for the given `url`.
+    pub fn clear(url: &Url) {
+        let _ = Entry::new(KEYRING_SERVICE, &Self::owner_token_key(url)).delete_password();
+        let _ = Entry::new(KEYRING_SERVICE, &Self::password_key(url)).delete_password();
+    }
+
+    /// Build the entry key used to store an owner token.
+    fn owner_token_key(url: &Url) -> String {
+        format!("{}#owner", url)
+    }
+
+    /// Build the entry key used to store a password.
+    fn password_key(url: &Url) -> String {
+        format!("{}#password", url)
+    }
+
+    /// Get the secret stored under the given entry `key`.
+    fn get(key: &str) -> Option<String> {
+        Entry::new(KEYRING_SERVICE, key).get_password().ok()
+    }
+
+    /// Set the secret stored under the given entry `key`.
+    fn set(key: &str, value: &str) {
+        let _ = Entry::new(KEYRING_SERVICE, key).set_password(value);
+    }
+}
+
+/// Wipe the owner token and password stored for the given `url`.
+///
+/// This is the implementation backing the `ffsend keyring clear <URL>`
+/// subcommand; the `cmd`/`cmd::matcher` definitions that parse that
+/// subcommand and call into this function are out of scope for this
+/// file.
+#[cfg(feature = "keyring")]
+pub fn keyring_clear(url: &Url) {
+    Secrets::clear(url);
+    print_success("Stored secrets for this share have been removed.");
+}
+
 /// Check for an emtpy password in the given `password`.
 /// If the password is emtpy the program will quit with an error unless
 /// forced.
@@ -207,6 +349,254 @@ pub fn check_empty_password(password: &str, matcher_main: &MainMatcher) {
     }
 }
 
+/// The Assuan error code pinentry reports when the user cancelled the
+/// prompt.
+const PINENTRY_CANCELLED: &str = "83886179";
+
+/// An error that occurred while communicating with a pinentry program.
+#[derive(Debug, Fail)]
+pub enum PinentryError {
+    /// Failed to spawn the pinentry program.
+    #[fail(display = "failed to invoke pinentry program")]
+    Spawn(#[cause] IoError),
+
+    /// Failed to read from or write to the pinentry program.
+    #[fail(display = "failed to communicate with pinentry program")]
+    Io(#[cause] IoError),
+
+    /// The pinentry program reported an unexpected protocol error.
+    #[fail(display = "pinentry program reported an error: {}", _0)]
+    Protocol(String),
+}
+
+/// Percent-decode the payload of an Assuan `D` line.
+fn pinentry_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = u8::from_str_radix(&input[i + 1..i + 3], 16) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Send an Assuan command line to the pinentry `stdin`.
+fn pinentry_send<W: Write>(stdin: &mut W, cmd: &str) -> Result<(), PinentryError> {
+    writeln!(stdin, "{}", cmd).map_err(PinentryError::Io)?;
+    stdin.flush().map_err(PinentryError::Io)
+}
+
+/// Read a single Assuan line from the pinentry `stdout`.
+fn pinentry_read_line<R: BufRead>(stdout: &mut R) -> Result<String, PinentryError> {
+    let mut line = String::new();
+    stdout.read_line(&mut line).map_err(PinentryError::Io)?;
+    Ok(line.trim_end().to_owned())
+}
+
+/// Whether the given Assuan line is a status (`S`) or comment (`#`) line
+/// that may be interleaved before the actual result, and should be
+/// ignored.
+fn pinentry_is_noise(line: &str) -> bool {
+    line.starts_with('S') || line.starts_with('#')
+}
+
+/// Expect an `OK` reply on the pinentry `stdout`, erroring on `ERR`.
+/// Interleaved status (`S`) and comment (`#`) lines are skipped.
+fn pinentry_expect_ok<R: BufRead>(stdout: &mut R) -> Result<(), PinentryError> {
+    loop {
+        let line = pinentry_read_line(stdout)?;
+        if pinentry_is_noise(&line) {
+            continue;
+        }
+        return if line.starts_with("OK") {
+            Ok(())
+        } else {
+            Err(PinentryError::Protocol(line))
+        };
+    }
+}
+
+/// Prompt for a secret using an external `pinentry` program, communicating
+/// over its Assuan-style protocol.
+///
+/// Returns `Ok(None)` if the user cancelled the prompt through pinentry.
+pub fn prompt_pinentry(program: &str, desc: &str, prompt: &str) -> Result<Option<String>, PinentryError> {
+    let mut child = Command::new(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(PinentryError::Spawn)?;
+
+    let mut stdin = child.stdin.take().expect("pinentry child has no stdin");
+    let mut stdout = BufReader::new(
+        child.stdout.take().expect("pinentry child has no stdout")
+    );
+
+    // Read the initial greeting
+    pinentry_expect_ok(&mut stdout)?;
+
+    pinentry_send(&mut stdin, &format!("SETDESC {}", desc))?;
+    pinentry_expect_ok(&mut stdout)?;
+
+    pinentry_send(&mut stdin, &format!("SETPROMPT {}", prompt))?;
+    pinentry_expect_ok(&mut stdout)?;
+
+    pinentry_send(&mut stdin, "GETPIN")?;
+
+    let mut secret = None;
+    loop {
+        let line = pinentry_read_line(&mut stdout)?;
+        if pinentry_is_noise(&line) {
+            continue;
+        } else if line.starts_with("D ") {
+            secret = Some(pinentry_decode(&line[2..]));
+        } else if line.starts_with("OK") {
+            break;
+        } else if line.starts_with("ERR") {
+            if line.contains(PINENTRY_CANCELLED) {
+                return Ok(None);
+            }
+            return Err(PinentryError::Protocol(line));
+        } else {
+            return Err(PinentryError::Protocol(line));
+        }
+    }
+
+    let _ = pinentry_send(&mut stdin, "BYE");
+
+    // No `D` line before `OK` means an empty secret was entered
+    Ok(Some(secret.unwrap_or_default()))
+}
+
+/// The maximum number of attempts allowed when confirming a password,
+/// before giving up with an error.
+const PASSWORD_CONFIRM_ATTEMPTS: usize = 3;
+
+/// A guard that puts the terminal into raw mode with bracketed paste
+/// enabled, and restores it when dropped.
+///
+/// Restoring on drop ensures the user's shell isn't left in raw mode if
+/// reading the password errors out or the process panics.
+#[cfg(feature = "rawmode")]
+struct RawModeGuard;
+
+#[cfg(feature = "rawmode")]
+impl RawModeGuard {
+    fn new() -> Result<Self, IoError> {
+        enable_raw_mode()?;
+        eprint!("\x1B[?2004h");
+        let _ = stderr().flush();
+        Ok(RawModeGuard)
+    }
+}
+
+#[cfg(feature = "rawmode")]
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        eprint!("\x1B[?2004l");
+        let _ = stderr().flush();
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Read a password directly from the terminal in raw mode, masking typed
+/// characters as they're entered.
+///
+/// Bracketed paste is enabled so a pasted password arrives as a single
+/// [`Event::Paste`] and is inserted atomically, rather than being
+/// interpreted key-by-key which could garble the secret or submit early
+/// on an embedded newline. This relies on crossterm's own
+/// `bracketed-paste` Cargo feature being enabled; see the `extern crate
+/// crossterm` declaration.
+#[cfg(feature = "rawmode")]
+fn prompt_password_rawmode(prompt: &str) -> Result<String, IoError> {
+    eprint!("{}", prompt);
+    let _ = stderr().flush();
+
+    let _guard = RawModeGuard::new()?;
+    let mut secret = String::new();
+
+    loop {
+        match read()? {
+            Event::Key(key) => match key.code {
+                KeyCode::Enter => break,
+                KeyCode::Backspace => {
+                    if secret.pop().is_some() {
+                        eprint!("\u{8} \u{8}");
+                        let _ = stderr().flush();
+                    }
+                },
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    drop(_guard);
+                    eprintln!();
+                    quit();
+                },
+                KeyCode::Char(c) => {
+                    secret.push(c);
+                    eprint!("*");
+                    let _ = stderr().flush();
+                },
+                _ => {},
+            },
+            Event::Paste(data) => {
+                eprint!("{}", "*".repeat(data.chars().count()));
+                let _ = stderr().flush();
+                secret.push_str(&data);
+            },
+            _ => {},
+        }
+    }
+
+    eprintln!();
+    Ok(secret)
+}
+
+/// Prompt for a single password entry, using the given `prompt` text.
+///
+/// If `--pinentry <PROGRAM>` (or its environment variable) is configured,
+/// the prompt is delegated to that external pinentry program instead of
+/// reading from the TTY directly. Otherwise, if the `rawmode` feature is
+/// enabled, a raw-mode terminal reader with bracketed paste support is
+/// used in favor of the line-based rpassword prompt.
+fn prompt_password_once(prompt: &str, main_matcher: &MainMatcher) -> String {
+    // Delegate to pinentry, if configured
+    if let Some(program) = main_matcher.pinentry() {
+        match prompt_pinentry(program, "Enter the password to use", prompt.trim_end()) {
+            Ok(Some(password)) => return password,
+            Ok(None) => quit(),
+            Err(err) => quit_error(err.context(
+                "Failed to read password through pinentry"
+            ), ErrorHints::default()),
+        }
+    }
+
+    #[cfg(feature = "rawmode")]
+    {
+        return match prompt_password_rawmode(prompt) {
+            Ok(password) => password,
+            Err(err) => quit_error(err.context(
+                "Failed to read password from password prompt"
+            ), ErrorHints::default()),
+        };
+    }
+
+    #[cfg(not(feature = "rawmode"))]
+    match prompt_password_stderr(prompt) {
+        Ok(password) => password,
+        Err(err) => quit_error(err.context(
+            "Failed to read password from password prompt"
+        ), ErrorHints::default()),
+    }
+}
+
 /// Prompt the user to enter a password.
 ///
 /// If `empty` is `false`, emtpy passwords aren't allowed unless forced.
@@ -223,12 +613,132 @@ pub fn prompt_password(main_matcher: &MainMatcher) -> String {
         );
     }
 
-    // Prompt for the password
-    match prompt_password_stderr("Password: ") {
-        Ok(password) => password,
+    prompt_password_once("Password: ", main_matcher)
+}
+
+/// Prompt the user to enter a password twice, requiring both entries to
+/// match before returning.
+///
+/// This should be used on the upload path, where a typo in an unconfirmed
+/// password would otherwise silently produce a file the recipient can't
+/// decrypt. The download path should keep using the single-entry
+/// [`prompt_password`].
+///
+/// Confirmation is skipped, falling back to a single prompt, when
+/// `assume_yes` is set. In `no_interact` mode this quits with an error, as
+/// no password may be prompted for.
+pub fn prompt_password_confirm(main_matcher: &MainMatcher) -> String {
+    // Quit with an error if we may not interact
+    if main_matcher.no_interact() {
+        quit_error_msg(
+            "Missing password, must be specified in no-interact mode",
+            ErrorHintsBuilder::default()
+                .password(true)
+                .verbose(false)
+                .build()
+                .unwrap(),
+        );
+    }
+
+    // Don't bother confirming if the user has already assumed yes
+    if main_matcher.assume_yes() {
+        return prompt_password_once("Password: ", main_matcher);
+    }
+
+    for _ in 0..PASSWORD_CONFIRM_ATTEMPTS {
+        let password = prompt_password_once("Password: ", main_matcher);
+        let confirm = prompt_password_once("Confirm password: ", main_matcher);
+
+        if password == confirm {
+            return password;
+        }
+
+        eprintln!("{} the passwords do not match, please try again", "error:".red().bold());
+    }
+
+    quit_error_msg(
+        "Too many incorrect password confirmations",
+        ErrorHints::default(),
+    );
+}
+
+/// The alphabet used to generate high-entropy random passwords.
+/// Consists of ASCII letters, digits and a selection of symbols.
+const GEN_PASSWORD_ALPHABET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%^&*()-_=+[]{}";
+
+/// The bundled BIP39-style wordlist used to generate mnemonic passphrases.
+const GEN_PASSWORD_WORDLIST: &str = include_str!("wordlist.txt");
+
+/// The default length for a generated high-entropy password.
+pub const GEN_PASSWORD_CHARS_DEFAULT: usize = 16;
+
+/// The default number of words for a generated mnemonic passphrase.
+pub const GEN_PASSWORD_WORDS_DEFAULT: usize = 6;
+
+/// The entropy, in bits, below which a generated password is considered
+/// weak and the user is warned.
+const GEN_PASSWORD_MIN_ENTROPY: f64 = 60.0;
+
+/// A mode to generate a random password in.
+#[derive(Debug, Clone, Copy)]
+pub enum PasswordMode {
+    /// Generate a high-entropy password of the given number of characters,
+    /// uniformly sampled from a printable ASCII alphabet.
+    Chars(usize),
+
+    /// Generate a mnemonic passphrase of the given number of words,
+    /// uniformly sampled from the bundled wordlist and joined with dashes.
+    Words(usize),
+}
+
+/// Get the bundled wordlist as a list of words.
+fn gen_password_wordlist() -> Vec<&'static str> {
+    GEN_PASSWORD_WORDLIST.lines().filter(|w| !w.is_empty()).collect()
+}
+
+/// Calculate the entropy, in bits, a password generated in the given
+/// `mode` would have.
+pub fn gen_password_entropy(mode: PasswordMode) -> f64 {
+    match mode {
+        PasswordMode::Chars(len) =>
+            len as f64 * (GEN_PASSWORD_ALPHABET.len() as f64).log2(),
+        PasswordMode::Words(words) =>
+            words as f64 * (gen_password_wordlist().len() as f64).log2(),
+    }
+}
+
+/// Generate a random password in the given `mode`, using the OS CSPRNG.
+///
+/// A warning is printed to stderr if the resulting password is considered
+/// weak.
+pub fn gen_password(mode: PasswordMode) -> String {
+    let entropy = gen_password_entropy(mode);
+    if entropy < GEN_PASSWORD_MIN_ENTROPY {
+        eprintln!(
+            "{} generated password has only ~{:.0} bits of entropy, consider generating a longer one",
+            "warning:".yellow().bold(),
+            entropy,
+        );
+    }
+
+    let mut rng = match OsRng::new() {
+        Ok(rng) => rng,
         Err(err) => quit_error(err.context(
-            "Failed to read password from password prompt"
+            "Failed to access the OS CSPRNG to generate a password"
         ), ErrorHints::default()),
+    };
+    match mode {
+        PasswordMode::Chars(len) => (0..len)
+            .map(|_| GEN_PASSWORD_ALPHABET[rng.gen_range(0, GEN_PASSWORD_ALPHABET.len())] as char)
+            .collect(),
+        PasswordMode::Words(words) => {
+            let list = gen_password_wordlist();
+            (0..words)
+                .map(|_| list[rng.gen_range(0, list.len())])
+                .collect::<Vec<_>>()
+                .join("-")
+        },
     }
 }
 
@@ -239,9 +749,23 @@ pub fn prompt_password(main_matcher: &MainMatcher) -> String {
 /// This method will prompt the user for a password, if one is required but
 /// wasn't set. An ignore message will be shown if it was not required while it
 /// was set.
+///
+/// If the `keyring` feature is enabled and not disabled through
+/// `--no-keyring`, the password stored for `url` is consulted before
+/// prompting on the download path. An upload always defines a new
+/// password, so the store is never read there; once the server has
+/// responded with the real share URL, the caller should persist it
+/// through [`Secrets::set_password`].
+///
+/// `upload` distinguishes the upload path, which offers `--gen-passphrase`
+/// generation, requires double-entry confirmation, and is checked through
+/// [`check_empty_password`], from the download path, which just prompts
+/// once for the existing password.
 pub fn ensure_password(
     password: &mut Option<String>,
     needs: bool,
+    upload: bool,
+    url: &Url,
     main_matcher: &MainMatcher,
 ) {
     // Return if we're fine
@@ -252,7 +776,48 @@ pub fn ensure_password(
     // Prompt for the password, or clear it if not required
     if needs {
         println!("This file is protected with a password.");
-        *password = Some(prompt_password(main_matcher));
+
+        // An upload always defines a new password, so only consult the
+        // store on the download path
+        #[cfg(feature = "keyring")]
+        {
+            if !upload && !main_matcher.no_keyring() {
+                if let Some(stored) = Secrets::get_password(url) {
+                    *password = Some(stored);
+                    return;
+                }
+            }
+        }
+
+        if upload {
+            if let Some(mode) = main_matcher.gen_password() {
+                let generated = gen_password(mode);
+                eprintln!("Generated password: {}", highlight(&generated));
+
+                #[cfg(feature = "clipboard")]
+                {
+                    if let Err(err) = set_clipboard(generated.clone()) {
+                        eprintln!("Failed to copy the generated password to the clipboard: {}", err);
+                    }
+                }
+
+                *password = Some(generated);
+            } else {
+                *password = Some(prompt_password_confirm(main_matcher));
+            }
+
+            // Applies to every upload-path outcome: generated, or
+            // confirmed through the prompt (including the assume-yes
+            // single-entry fallback)
+            check_empty_password(password.as_ref().unwrap(), main_matcher);
+
+            // Not persisted here: the real share URL is only known once
+            // the server responds to the upload. The caller should save
+            // the password through `Secrets::set_password` once that
+            // response comes in.
+        } else {
+            *password = Some(prompt_password(main_matcher));
+        }
     } else {
         println!("Ignoring password, it is not required");
         *password = None;
@@ -379,10 +944,24 @@ pub fn prompt_owner_token(main_matcher: &MainMatcher) -> String {
 /// parameter.
 ///
 /// This method will prompt the user for the token, if it wasn't set.
+///
+/// If the `keyring` feature is enabled and not disabled through
+/// `--no-keyring`, the token stored for `url` is consulted before
+/// prompting the user, and the confirmed token is saved back to the
+/// store afterwards.
 pub fn ensure_owner_token(
     token: &mut Option<String>,
+    url: &Url,
     main_matcher: &MainMatcher,
 ) {
+    // Consult the secure credential store before prompting
+    #[cfg(feature = "keyring")]
+    {
+        if token.is_none() && !main_matcher.no_keyring() {
+            *token = Secrets::get_owner_token(url);
+        }
+    }
+
     // Check whehter we allow interaction
     let interact = !main_matcher.no_interact();
 
@@ -419,6 +998,15 @@ pub fn ensure_owner_token(
             break;
         }
     }
+
+    // Persist the now-confirmed owner token, so future invocations for
+    // this share URL no longer need to prompt
+    #[cfg(feature = "keyring")]
+    {
+        if !main_matcher.no_keyring() {
+            Secrets::set_owner_token(url, token.as_ref().unwrap());
+        }
+    }
 }
 
 /// Format the given number of bytes readable for humans.